@@ -1,16 +1,165 @@
-use std::{fs, io, path::PathBuf};
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
 
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+const DEFAULT_ENDPOINT: &str = "http://localhost:8765";
+const DEFAULT_QUESTION_TOKEN: &str = "Q: ";
+const DEFAULT_ANSWER_TOKEN: &str = "A: ";
+
 #[derive(Error, Debug)]
 pub enum AnkiSyncConfigError {
     #[error("Unable to find env var {0}")]
     EnvVarMissing(String),
     #[error("Error reading config file: {0}")]
     ConfigFileError(#[from] io::Error),
+    #[error("Error parsing sync state file: {0}")]
+    StateParseError(#[from] serde_json::Error),
+    #[error("Error parsing config file: {0}")]
+    TomlParseError(#[from] toml::de::Error),
+    #[error("Invalid file glob: {0}")]
+    GlobPatternError(#[from] glob::PatternError),
+    #[error("Error reading globbed file: {0}")]
+    GlobError(#[from] glob::GlobError),
+}
+
+fn default_endpoint() -> String {
+    DEFAULT_ENDPOINT.to_owned()
+}
+
+fn default_question_token() -> String {
+    DEFAULT_QUESTION_TOKEN.to_owned()
+}
+
+fn default_answer_token() -> String {
+    DEFAULT_ANSWER_TOKEN.to_owned()
+}
+
+/// Structured TOML configuration, e.g.:
+///
+/// ```toml
+/// files = ["/home/user/notes/**/*.md"]
+/// endpoint = "http://localhost:8765"
+/// question_token = "Q: "
+/// answer_token = "A: "
+/// key = "my-anki-connect-api-key"
+/// ```
+///
+/// A leading `~/` in a glob is expanded against `$HOME` before matching, since the `glob`
+/// crate treats `~` as a literal path component rather than expanding it.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AnkiSyncConfig {
+    /// Globs matched to find markdown files to sync.
+    pub files: Vec<String>,
+    /// AnkiConnect endpoint to post requests to.
+    pub endpoint: String,
+    /// Token marking the start of a question, e.g. `"Q: "`.
+    pub question_token: String,
+    /// Token marking the start of an answer, e.g. `"A: "`.
+    pub answer_token: String,
+    /// Optional AnkiConnect API key, sent with every request once configured.
+    pub key: Option<String>,
+}
+
+impl Default for AnkiSyncConfig {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            endpoint: default_endpoint(),
+            question_token: default_question_token(),
+            answer_token: default_answer_token(),
+            key: None,
+        }
+    }
+}
+
+impl AnkiSyncConfig {
+    pub fn load(config_path: &Path) -> Result<Self, AnkiSyncConfigError> {
+        let config_contents = fs::read_to_string(config_path)?;
+        Ok(toml::from_str(&config_contents)?)
+    }
+
+    /// Expand `files` into concrete paths, in the order their globs are listed.
+    pub fn resolved_files(&self) -> Result<Vec<PathBuf>, AnkiSyncConfigError> {
+        let mut paths = Vec::new();
+        for pattern in &self.files {
+            for entry in glob::glob(&expand_tilde(pattern))? {
+                paths.push(entry?);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// Expand a leading `~/` (or bare `~`) in a glob pattern against `$HOME`, since `glob::glob`
+/// treats `~` as a literal path component and otherwise silently matches nothing.
+fn expand_tilde(pattern: &str) -> String {
+    let Some(home) = env::var_os("HOME") else {
+        return pattern.to_owned();
+    };
+    let home = home.to_string_lossy();
+
+    if pattern == "~" {
+        home.into_owned()
+    } else if let Some(rest) = pattern.strip_prefix("~/") {
+        format!("{}/{}", home, rest)
+    } else {
+        pattern.to_owned()
+    }
+}
+
+/// Build a weak validator for a file, in the same spirit as an HTTP ETag: the file's length
+/// and last-modification time are combined so an unchanged file produces the same validator
+/// across runs, while any edit (even one that doesn't change the byte count) changes it.
+///
+/// This only looks at `path` itself, not any media files it references, so editing a referenced
+/// image or audio file's bytes without touching the markdown text doesn't change the validator.
+pub fn file_validator(path: &Path) -> io::Result<String> {
+    let metadata = fs::metadata(path)?;
+    let mtime = FileTime::from_last_modification_time(&metadata);
+    Ok(format!(
+        "{:x}-{:x}.{:x}",
+        metadata.len(),
+        mtime.seconds(),
+        mtime.nanoseconds()
+    ))
+}
+
+/// Tracks the last-synced validator for each file so unchanged files can be skipped on
+/// subsequent runs instead of being re-parsed and re-posted every time.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    validators: HashMap<PathBuf, String>,
 }
 
-pub fn load_config(config_path: &PathBuf) -> Result<Vec<PathBuf>, AnkiSyncConfigError> {
-    let config_contents = fs::read_to_string(config_path)?;
-    Ok(config_contents.lines().map(PathBuf::from).collect())
+impl SyncState {
+    /// Load sync state from `state_path`, or an empty state if the file doesn't exist yet.
+    pub fn load(state_path: &Path) -> Result<Self, AnkiSyncConfigError> {
+        match fs::read_to_string(state_path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, state_path: &Path) -> Result<(), AnkiSyncConfigError> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(state_path, contents)?;
+        Ok(())
+    }
+
+    /// Returns the validator recorded for `file` on its last successful sync, if any.
+    pub fn validator(&self, file: &Path) -> Option<&str> {
+        self.validators.get(file).map(String::as_str)
+    }
+
+    pub fn set_validator(&mut self, file: &Path, validator: String) {
+        self.validators.insert(file.to_path_buf(), validator);
+    }
 }
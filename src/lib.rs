@@ -1,22 +1,39 @@
 use std::{fmt, io};
 use std::fmt::Formatter;
+use base64::Engine;
 use reqwest::Response;
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::fs::File;
+use sha1::{Digest as _, Sha1};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use log::{debug, error};
+use std::path::{Path, PathBuf};
+use log::{debug, error, info, warn};
 use thiserror::Error;
 
+pub mod config;
+mod store;
+use config::{file_validator, AnkiSyncConfig, AnkiSyncConfigError, SyncState};
+use store::{StoreError, StoredNote, SyncStore};
+
 // md parsing constants
 const METADATA_DELIM: &str = "---";
 const METADATA_KEY_VALUE_DELIM: &str = ":";
 const DECK: &str = "deck";
 
+// media constants
+const AUDIO_EXTENSIONS: [&str; 3] = ["mp3", "wav", "ogg"];
+
 // api constants
 const API_VERSION: i32 = 6;
 
+/// Hex-encode bytes, e.g. for naming content-addressed media files.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// Anki note models
 #[derive(Clone, Debug)]
 enum Model {
@@ -50,36 +67,65 @@ struct AddNotesResponse {
     error: Option<String>,
 }
 
+/// storeMediaFile response type
+#[derive(Debug, Deserialize)]
+struct StoreMediaFileResponse {
+    error: Option<String>,
+}
+
+/// updateNoteFields response type
+#[derive(Debug, Deserialize)]
+struct UpdateNoteFieldsResponse {
+    error: Option<String>,
+}
+
+/// deleteNotes response type
+#[derive(Debug, Deserialize)]
+struct DeleteNotesResponse {
+    error: Option<String>,
+}
+
+/// A local media file (image or audio) referenced by a note, content-addressed
+/// by the hash of its bytes so re-syncing the same asset is idempotent.
+#[derive(Clone, Debug)]
+struct MediaAsset {
+    filename: String,
+    data: Vec<u8>,
+}
+
 /// Small client for using anki-connect's APIs
 struct AnkiConnectClient {
     endpoint: String,
+    key: Option<String>,
     client: reqwest::Client,
 }
 
 impl AnkiConnectClient {
-    pub fn new(endpoint: &str) -> Self {
+    pub fn new(endpoint: &str, key: Option<String>) -> Self {
         Self {
             endpoint: endpoint.to_owned(),
+            key,
             client: reqwest::Client::new(),
         }
     }
 
-    pub fn default() -> Self {
-        Self::new("http://localhost:8765")
-    }
-
     pub async fn post(&self, body: &Value) -> Result<Response, reqwest::Error> {
+        let mut body = body.clone();
+        if let (Some(key), Value::Object(map)) = (&self.key, &mut body) {
+            map.insert("key".to_owned(), Value::String(key.clone()));
+        }
+
         debug!("Sending post with body: {:?}", body);
         let response = self.client
             .post(&self.endpoint)
-            .json(body)
+            .json(&body)
             .send()
             .await;
         debug!("Received response: {:?}", response);
         response
     }
 
-    pub async fn add_notes(&self, notes: Vec<ParsedNote>) -> Result<(), ApiError> {
+    pub async fn add_notes(&self, notes: &[ParsedNote]) -> Result<Vec<Option<i64>>, ApiError> {
         let notes_json: Vec<Value> = notes.iter().map(|n| n.to_json()).collect();
         let body = json!({
             "action": "addNotes",
@@ -91,13 +137,74 @@ impl AnkiConnectClient {
 
         let response = self.post(&body).await?;
         let add_notes_response = response.json::<AddNotesResponse>().await?;
-        
+
         match add_notes_response.error {
             Some(e) => Err(ApiError::ResponseError(e)),
             None => {
                 debug!("Response: {:?}", add_notes_response.result);
-                Ok(())
+                Ok(add_notes_response.result)
+            }
+        }
+    }
+
+    pub async fn update_note_fields(&self, note_id: i64, note: &ParsedNote) -> Result<(), ApiError> {
+        let body = json!({
+            "action": "updateNoteFields",
+            "version": API_VERSION,
+            "params": {
+                "note": {
+                    "id": note_id,
+                    "fields": {
+                        "Front": note.question,
+                        "Back": note.answer
+                    }
+                }
+            }
+        });
+
+        let response = self.post(&body).await?;
+        let update_response = response.json::<UpdateNoteFieldsResponse>().await?;
+
+        match update_response.error {
+            Some(e) => Err(ApiError::ResponseError(e)),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn delete_notes(&self, note_ids: &[i64]) -> Result<(), ApiError> {
+        let body = json!({
+            "action": "deleteNotes",
+            "version": API_VERSION,
+            "params": {
+                "notes": note_ids
             }
+        });
+
+        let response = self.post(&body).await?;
+        let delete_response = response.json::<DeleteNotesResponse>().await?;
+
+        match delete_response.error {
+            Some(e) => Err(ApiError::ResponseError(e)),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn store_media_file(&self, asset: &MediaAsset) -> Result<(), ApiError> {
+        let body = json!({
+            "action": "storeMediaFile",
+            "version": API_VERSION,
+            "params": {
+                "filename": asset.filename,
+                "data": base64::engine::general_purpose::STANDARD.encode(&asset.data)
+            }
+        });
+
+        let response = self.post(&body).await?;
+        let store_response = response.json::<StoreMediaFileResponse>().await?;
+
+        match store_response.error {
+            Some(e) => Err(ApiError::ResponseError(e)),
+            None => Ok(()),
         }
     }
 }
@@ -108,9 +215,32 @@ pub struct ParsedNote {
     model: Model,
     question: String,
     answer: String,
+    media: Vec<MediaAsset>,
 }
 
 impl ParsedNote {
+    /// A stable anchor identifying this note across syncs, independent of its position in the
+    /// file: the SHA1 of its deck and question. Reordering or inserting/removing other notes
+    /// doesn't change a note's anchor, so reconciliation isn't fooled by position shifts. Only
+    /// editing the question itself (or the file's deck) changes the anchor, which is treated as
+    /// deleting the old note and adding a new one.
+    fn anchor_key(&self) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(self.deck.0.as_bytes());
+        hasher.update(self.question.as_bytes());
+        to_hex(&hasher.finalize())
+    }
+
+    /// A key identifying this note's content: the SHA1 of its deck, question, and answer, so
+    /// an edit to any of those fields is detected as a content change.
+    fn content_key(&self) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(self.deck.0.as_bytes());
+        hasher.update(self.question.as_bytes());
+        hasher.update(self.answer.as_bytes());
+        to_hex(&hasher.finalize())
+    }
+
     fn to_json(&self) -> Value {
         json!({
             "deckName": self.deck.0,
@@ -202,6 +332,7 @@ struct Parser {
     answer: String,   // internal state of the current answer being parsed
     parsed: Vec<ParsedNote>, // the results that will be retrieved in `finalize()`
     line_num: u128, // counter to let us keep track of the line number in a file
+    base_dir: PathBuf, // directory local media references are resolved against
 }
 
 impl Parser {
@@ -217,9 +348,22 @@ impl Parser {
             answer: String::new(),
             parsed: Vec::new(),
             line_num: 0,
+            base_dir: PathBuf::new(),
         }
     }
 
+    /// Reset all per-file state so a prior file's parse error (which may leave `state` and the
+    /// question/answer buffers mid-parse) can't corrupt the next file parsed with this `Parser`.
+    fn begin_file(&mut self, base_dir: PathBuf) {
+        self.state = ParseState::Start;
+        self.deck = None;
+        self.question.clear();
+        self.answer.clear();
+        self.parsed.clear();
+        self.line_num = 0;
+        self.base_dir = base_dir;
+    }
+
     fn handle_event(&mut self, event: &str) -> Result<(), ParseError> {
         // pass things from self to parse_event_type to avoid having a mutable and immutable borrow
         // for self in this function. probably a better way to fix it but oh well
@@ -269,11 +413,16 @@ impl Parser {
     fn finalize_note(&mut self) -> Result<(), ParseError> {
         let deck = self.deck.as_ref().ok_or(ParseError::MissingDeck)?;
 
+        let (question_src, mut media) = Self::resolve_media(&self.question, &self.base_dir);
+        let (answer_src, answer_media) = Self::resolve_media(&self.answer, &self.base_dir);
+        media.extend(answer_media);
+
         self.parsed.push(ParsedNote {
             model: Model::Basic,
             deck: deck.clone(),
-            question: markdown::to_html(&self.question),
-            answer: markdown::to_html(&self.answer),
+            question: markdown::to_html(&question_src),
+            answer: markdown::to_html(&answer_src),
+            media,
         });
 
         self.question.clear();
@@ -282,6 +431,78 @@ impl Parser {
         Ok(())
     }
 
+    /// Scan `src` for local `![alt](path)` media references, read each file relative to
+    /// `base_dir`, and replace the reference with a content-addressed filename so repeated
+    /// syncs of unchanged assets reuse the same stored file. Audio references are rewritten
+    /// to Anki's `[sound:...]` field syntax instead of an image tag. Remote (`scheme://`)
+    /// references are left untouched.
+    fn resolve_media(src: &str, base_dir: &Path) -> (String, Vec<MediaAsset>) {
+        let mut rewritten = String::with_capacity(src.len());
+        let mut media = Vec::new();
+        let mut rest = src;
+
+        while let Some(bang_idx) = rest.find("![") {
+            let (before, after_bang) = rest.split_at(bang_idx);
+            rewritten.push_str(before);
+
+            let Some(close_bracket) = after_bang.find(']') else {
+                rewritten.push_str(after_bang);
+                rest = "";
+                break;
+            };
+
+            let after_bracket = &after_bang[close_bracket + 1..];
+            if !after_bracket.starts_with('(') {
+                rewritten.push_str(&after_bang[..close_bracket + 1]);
+                rest = after_bracket;
+                continue;
+            }
+
+            let Some(close_paren) = after_bracket.find(')') else {
+                rewritten.push_str(&after_bang[..close_bracket + 1]);
+                rest = after_bracket;
+                continue;
+            };
+
+            let alt = &after_bang[2..close_bracket];
+            let link = &after_bracket[1..close_paren];
+            rest = &after_bracket[close_paren + 1..];
+
+            if link.contains("://") {
+                rewritten.push_str(&format!("![{}]({})", alt, link));
+                continue;
+            }
+
+            let asset_path = base_dir.join(link);
+            let data = match fs::read(&asset_path) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Unable to read media file {:?}, leaving reference as-is: {}", asset_path, e);
+                    rewritten.push_str(&format!("![{}]({})", alt, link));
+                    continue;
+                }
+            };
+            let hash = to_hex(&Sha256::digest(&data));
+            let ext = Path::new(link).extension().and_then(|e| e.to_str()).unwrap_or("");
+            let filename = if ext.is_empty() {
+                hash
+            } else {
+                format!("{}.{}", hash, ext)
+            };
+
+            if AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
+                rewritten.push_str(&format!("[sound:{}]", filename));
+            } else {
+                rewritten.push_str(&format!("![{}]({})", alt, filename));
+            }
+
+            media.push(MediaAsset { filename, data });
+        }
+
+        rewritten.push_str(rest);
+        (rewritten, media)
+    }
+
     fn handle_metadata_delim(&mut self) -> Result<(), ParseError> {
         match &self.state {
             ParseState::Start | ParseState::InMetadata => {
@@ -392,14 +613,13 @@ impl AnkiMarkdownHandler {
         }
     }
 
-    fn default() -> Self {
-        Self::new("Q: ", "A: ")
-    }
-
     fn parse_file(&mut self, path: &PathBuf) -> Result<Vec<ParsedNote>, ParseError> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
 
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.parser.begin_file(base_dir);
+
         for line in reader.lines() {
             let event = line?;
             self.parser.handle_event(&event)?;
@@ -415,24 +635,168 @@ pub enum AnkiSyncError {
     ApiError(#[from] ApiError),
     #[error("Received parsing error: {0}")]
     ParseError(#[from] ParseError),
+    #[error("Config error: {0}")]
+    ConfigError(#[from] AnkiSyncConfigError),
+    #[error("Sync store error: {0}")]
+    StoreError(#[from] StoreError),
+    #[error("IO error: {0}")]
+    IOError(#[from] io::Error),
 }
 
 pub struct AnkiSync {
     anki_client: AnkiConnectClient,
     md_handler: AnkiMarkdownHandler,
+    sync_state: SyncState,
+    state_path: PathBuf,
+    sync_store: SyncStore,
 }
 
 impl AnkiSync {
-    pub fn new() -> Self {
-        Self {
-            anki_client: AnkiConnectClient::default(),
-            md_handler: AnkiMarkdownHandler::default(),
-        }
+    pub fn new(
+        config: &AnkiSyncConfig,
+        state_path: PathBuf,
+        db_path: PathBuf,
+    ) -> Result<Self, AnkiSyncError> {
+        Ok(Self {
+            anki_client: AnkiConnectClient::new(&config.endpoint, config.key.clone()),
+            md_handler: AnkiMarkdownHandler::new(&config.question_token, &config.answer_token),
+            sync_state: SyncState::load(&state_path)?,
+            state_path,
+            sync_store: SyncStore::open(&db_path)?,
+        })
     }
 
-    pub async fn sync_file(&mut self, file: &PathBuf) -> Result<(), AnkiSyncError> {
+    /// Known gap: the validator only covers the markdown file itself, so editing a referenced
+    /// media file's bytes without touching the `.md` text that references it leaves this file
+    /// looking unchanged and skips the re-upload. Use `--force` (or touch the markdown file) to
+    /// pick up media-only edits.
+    pub async fn sync_file(&mut self, file: &PathBuf, force: bool) -> Result<(), AnkiSyncError> {
+        let validator = file_validator(file)?;
+        if !force && self.sync_state.validator(file) == Some(validator.as_str()) {
+            info!("Skipping unchanged file {:?}", file);
+            return Ok(());
+        }
+
         let parsed_notes = self.md_handler.parse_file(file)?;
-        self.anki_client.add_notes(parsed_notes).await?;
+
+        let mut uploaded = HashSet::new();
+        for note in &parsed_notes {
+            for asset in &note.media {
+                if uploaded.insert(asset.filename.clone()) {
+                    self.anki_client.store_media_file(asset).await?;
+                }
+            }
+        }
+
+        self.reconcile_notes(file, parsed_notes).await?;
+
+        self.sync_state.set_validator(file, validator);
+        self.sync_state.save(&self.state_path)?;
+
+        Ok(())
+    }
+
+    /// Diff freshly parsed notes against the stored set for `file` by anchor key (deck +
+    /// question), not by position: new anchors are added, anchors whose content key changed
+    /// are updated in place, and anchors that no longer exist are deleted. Keying by anchor
+    /// instead of array position means inserting or removing a card no longer shifts every
+    /// later note onto the wrong stored Anki note ID. Editing the question itself changes the
+    /// anchor, so that's still seen as a delete-and-add rather than an update - there's no way
+    /// to tell that case apart from "note removed, unrelated note added" without a stable
+    /// per-card identifier the markdown format doesn't have.
+    ///
+    /// Two notes sharing the same deck and question would otherwise collide on the same anchor;
+    /// disambiguate repeats by the order they appear in the file, so duplicates are still
+    /// tracked as distinct notes across syncs as long as their relative order doesn't change.
+    async fn reconcile_notes(
+        &mut self,
+        file: &PathBuf,
+        parsed_notes: Vec<ParsedNote>,
+    ) -> Result<(), AnkiSyncError> {
+        let source_file = file.to_string_lossy().to_string();
+        let mut stored: HashMap<String, StoredNote> = self
+            .sync_store
+            .notes_for_file(&source_file)?
+            .into_iter()
+            .map(|note| (note.anchor_key.clone(), note))
+            .collect();
+        // The legacy table is only a fallback until this file has rows under the new scheme.
+        let legacy = if stored.is_empty() {
+            self.sync_store.legacy_notes_for_file(&source_file)?
+        } else {
+            HashMap::new()
+        };
+
+        let mut to_add_keys = Vec::new();
+        let mut to_add_notes = Vec::new();
+        let mut anchor_repeats: HashMap<String, usize> = HashMap::new();
+
+        for (position, note) in parsed_notes.into_iter().enumerate() {
+            let base_anchor = note.anchor_key();
+            let repeat = anchor_repeats.entry(base_anchor.clone()).or_insert(0);
+            let anchor_key = if *repeat == 0 {
+                base_anchor
+            } else {
+                format!("{}-{}", base_anchor, repeat)
+            };
+            *repeat += 1;
+
+            let content_key = note.content_key();
+
+            match stored.remove(&anchor_key) {
+                Some(existing) if existing.content_key == content_key => {}
+                Some(existing) => {
+                    self.anki_client
+                        .update_note_fields(existing.anki_note_id, &note)
+                        .await?;
+                    self.sync_store.upsert(
+                        &source_file,
+                        &anchor_key,
+                        &content_key,
+                        existing.anki_note_id,
+                    )?;
+                }
+                // Not found under the new scheme - if this is the first sync after upgrading
+                // from the position-keyed schema and the note at this same position has the
+                // exact same content as before, carry its Anki note ID forward instead of
+                // re-adding (and duplicating) it.
+                None => match legacy.get(&(position as i64)) {
+                    Some((legacy_content_key, legacy_anki_id))
+                        if *legacy_content_key == content_key =>
+                    {
+                        self.sync_store.upsert(
+                            &source_file,
+                            &anchor_key,
+                            &content_key,
+                            *legacy_anki_id,
+                        )?;
+                    }
+                    _ => {
+                        to_add_keys.push((anchor_key, content_key));
+                        to_add_notes.push(note);
+                    }
+                },
+            }
+        }
+
+        if !stored.is_empty() {
+            let stale_ids: Vec<i64> = stored.values().map(|note| note.anki_note_id).collect();
+            self.anki_client.delete_notes(&stale_ids).await?;
+            for anchor_key in stored.keys() {
+                self.sync_store.delete(&source_file, anchor_key)?;
+            }
+        }
+
+        if !to_add_notes.is_empty() {
+            let ids = self.anki_client.add_notes(&to_add_notes).await?;
+            for ((anchor_key, content_key), id) in to_add_keys.iter().zip(ids) {
+                if let Some(id) = id {
+                    self.sync_store
+                        .upsert(&source_file, anchor_key, content_key, id)?;
+                }
+            }
+        }
+
         Ok(())
     }
 }
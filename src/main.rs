@@ -1,18 +1,30 @@
 use anki_md_sync::{
-    config::{load_config, AnkiSyncConfigError},
+    config::{AnkiSyncConfig, AnkiSyncConfigError},
     AnkiSync,
 };
 use clap::{command, Arg, ArgMatches};
 use env_logger::Builder;
 use log::{error, info, LevelFilter};
+use notify::{RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::time::{Duration, Instant};
 use std::{env, error::Error, path::PathBuf};
 
 const FILES_ARG: &str = "files";
 const DEBUG_ARG: &str = "debug";
 const CONFIG_ARG: &str = "config";
+const FORCE_ARG: &str = "force";
+const WATCH_ARG: &str = "watch";
 
 const HOME_VAR: &str = "HOME";
-const CONFIG_PATH: &str = ".config/anki-md-sync";
+const CONFIG_PATH: &str = ".config/anki-md-sync.toml";
+const STATE_PATH: &str = ".config/anki-md-sync.state.json";
+const DB_PATH: &str = ".config/anki-md-sync.db";
+
+// don't re-sync a file more than once within this window, to collapse the burst of
+// write/save events an editor can fire for a single save
+const DEBOUNCE: Duration = Duration::from_millis(500);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -26,43 +38,139 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
     logging_builder.filter_level(log_level).init();
 
-    let mut anki_sync = AnkiSync::default();
+    let force = *args.get_one::<bool>(FORCE_ARG).unwrap();
 
-    let mut files: Vec<PathBuf> = Vec::new();
+    let home =
+        env::var_os(HOME_VAR).ok_or(AnkiSyncConfigError::EnvVarMissing(HOME_VAR.to_owned()))?;
 
-    if let Some(file_paths) = args.remove_many::<PathBuf>(FILES_ARG) {
-        files.extend(file_paths);
-    }
+    let mut state_path = PathBuf::new();
+    state_path.push(&home);
+    state_path.push(STATE_PATH);
+
+    let mut db_path = PathBuf::new();
+    db_path.push(&home);
+    db_path.push(DB_PATH);
 
-    if let Some(true) = args.get_one::<bool>(CONFIG_ARG) {
+    let mut config = AnkiSyncConfig::default();
+    let mut files: Vec<PathBuf> = Vec::new();
+
+    if *args.get_one::<bool>(CONFIG_ARG).unwrap() {
         let mut config_path = PathBuf::new();
-        let home =
-            env::var_os(HOME_VAR).ok_or(AnkiSyncConfigError::EnvVarMissing(HOME_VAR.to_owned()))?;
-        config_path.push(home);
+        config_path.push(&home);
         config_path.push(CONFIG_PATH);
 
         info!("Reading config file at {:?}", config_path);
 
-        let found_paths = load_config(&config_path)?;
-        for path in found_paths {
+        config = AnkiSyncConfig::load(&config_path)?;
+
+        let found_paths = config.resolved_files()?;
+        for path in &found_paths {
             info!("Found file from config: {:?}", path);
         }
+        files.extend(found_paths);
+    }
+
+    let mut anki_sync = AnkiSync::new(&config, state_path, db_path)?;
 
-        files.extend(load_config(&config_path)?);
+    if let Some(file_paths) = args.remove_many::<PathBuf>(FILES_ARG) {
+        files.extend(file_paths);
     }
 
     if files.is_empty() {
         info!("No files specified to sync!");
     }
 
+    // Canonicalize once so the same path value is used as the sync-state/store key, the
+    // watcher registration, and the path notify reports back in its events - otherwise a
+    // relative `-f` path and notify's (often absolutized) event path are treated as two
+    // different files and every edit looks like a brand-new, never-before-synced note.
+    let mut canonical_files = Vec::with_capacity(files.len());
     for f in files {
+        match fs::canonicalize(&f) {
+            Ok(canonical) => canonical_files.push(canonical),
+            Err(e) => error!("Unable to resolve path {:?}, skipping it: {}", f, e),
+        }
+    }
+    let files = canonical_files;
+
+    for f in &files {
         info!("Syncing file {:?}...", f);
-        match anki_sync.sync_file(&f).await {
+        match anki_sync.sync_file(f, force).await {
             Ok(_) => info!("Done syncing file {:?}!", f),
             Err(e) => error!("Error while syncing: {}", e),
         }
     }
 
+    if *args.get_one::<bool>(WATCH_ARG).unwrap() {
+        watch_files(&mut anki_sync, &files, force).await?;
+    }
+
+    Ok(())
+}
+
+/// Keep the process alive, re-syncing a tracked file whenever it changes on disk. Events are
+/// debounced per-file so a single save that fires several filesystem events only triggers one
+/// re-sync, and a failed sync is logged without breaking out of the loop.
+///
+/// Watches are registered on each tracked file's *parent directory* rather than the file
+/// itself: editors commonly save atomically (write a temp file, then rename it over the
+/// original), which replaces the inode a direct file watch is attached to and leaves it
+/// watching nothing. Directory events are filtered back down to the tracked file set.
+async fn watch_files(
+    anki_sync: &mut AnkiSync,
+    files: &[PathBuf],
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+
+    let tracked: HashSet<PathBuf> = files.iter().cloned().collect();
+
+    let mut watched_dirs = HashSet::new();
+    for f in files {
+        if let Some(dir) = f.parent() {
+            if watched_dirs.insert(dir.to_path_buf()) {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            }
+        }
+    }
+
+    info!("Watching {} file(s) for changes...", files.len());
+
+    let mut last_synced: HashMap<PathBuf, Instant> = HashMap::new();
+
+    for event in rx {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Watch error: {}", e);
+                continue;
+            }
+        };
+
+        for path in event.paths {
+            let path = fs::canonicalize(&path).unwrap_or(path);
+            if !tracked.contains(&path) {
+                continue;
+            }
+
+            let now = Instant::now();
+            if last_synced
+                .get(&path)
+                .is_some_and(|last| now.duration_since(*last) < DEBOUNCE)
+            {
+                continue;
+            }
+            last_synced.insert(path.clone(), now);
+
+            info!("Detected change in {:?}, re-syncing...", path);
+            match anki_sync.sync_file(&path, force).await {
+                Ok(_) => info!("Done syncing file {:?}!", path),
+                Err(e) => error!("Error while syncing: {}", e),
+            }
+        }
+    }
+
     Ok(())
 }
 
@@ -87,5 +195,19 @@ fn parse_args() -> ArgMatches {
                 .action(clap::ArgAction::SetTrue)
                 .help("Use markdown files specified in config file"),
         )
+        .arg(
+            Arg::new(FORCE_ARG)
+                .short('F')
+                .long("force")
+                .action(clap::ArgAction::SetTrue)
+                .help("Bypass the incremental sync cache and re-sync every file"),
+        )
+        .arg(
+            Arg::new(WATCH_ARG)
+                .short('w')
+                .long("watch")
+                .action(clap::ArgAction::SetTrue)
+                .help("Keep running and re-sync files as they change on disk"),
+        )
         .get_matches()
 }
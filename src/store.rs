@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rusqlite::Connection;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Sqlite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+}
+
+/// Numbered schema migrations, applied in order against `PRAGMA user_version` so each is run
+/// exactly once per database, the same scheme other Rust tools use to manage on-disk schema.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE notes (
+        source_file TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        note_key TEXT NOT NULL,
+        anki_note_id INTEGER NOT NULL,
+        PRIMARY KEY (source_file, position)
+    );
+",
+    "
+    -- Keying notes by their position in the file meant inserting or deleting a card shifted
+    -- every later position, reassigning existing Anki note IDs to the wrong cards. Replace the
+    -- position key with a stable anchor (deck + question) that survives reordering, tracked
+    -- separately from the content key used to detect when a note's fields actually changed.
+    --
+    -- The old table is kept around (renamed, not dropped) rather than lost: SyncStore uses it
+    -- once, as a one-time fallback, to carry existing Anki note IDs forward into the new
+    -- scheme instead of re-adding every already-synced note as a duplicate.
+    ALTER TABLE notes RENAME TO notes_legacy_v1;
+    CREATE TABLE notes (
+        source_file TEXT NOT NULL,
+        anchor_key TEXT NOT NULL,
+        content_key TEXT NOT NULL,
+        anki_note_id INTEGER NOT NULL,
+        PRIMARY KEY (source_file, anchor_key)
+    );
+",
+];
+
+/// A previously-synced note: the stable anchor it was last recorded under, the content key it
+/// had at that point, and the Anki note ID it was assigned.
+#[derive(Clone, Debug)]
+pub struct StoredNote {
+    pub anchor_key: String,
+    pub content_key: String,
+    pub anki_note_id: i64,
+}
+
+/// SQLite-backed store mapping each source file's notes to the Anki note IDs they were synced
+/// to, so future syncs can update or delete existing notes instead of only ever adding new ones.
+pub struct SyncStore {
+    conn: Connection,
+}
+
+impl SyncStore {
+    pub fn open(db_path: &Path) -> Result<Self, StoreError> {
+        let conn = Connection::open(db_path)?;
+        Self::migrate(&conn)?;
+        Ok(Self { conn })
+    }
+
+    fn migrate(conn: &Connection) -> Result<(), StoreError> {
+        let current_version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            let version = (i + 1) as i64;
+            if version > current_version {
+                conn.execute_batch(migration)?;
+                conn.pragma_update(None, "user_version", version)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn notes_for_file(&self, source_file: &str) -> Result<Vec<StoredNote>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT anchor_key, content_key, anki_note_id FROM notes WHERE source_file = ?1",
+        )?;
+        let rows = stmt.query_map([source_file], |row| {
+            Ok(StoredNote {
+                anchor_key: row.get(0)?,
+                content_key: row.get(1)?,
+                anki_note_id: row.get(2)?,
+            })
+        })?;
+
+        rows.collect::<Result<Vec<_>, _>>().map_err(StoreError::from)
+    }
+
+    pub fn upsert(
+        &self,
+        source_file: &str,
+        anchor_key: &str,
+        content_key: &str,
+        anki_note_id: i64,
+    ) -> Result<(), StoreError> {
+        self.conn.execute(
+            "INSERT INTO notes (source_file, anchor_key, content_key, anki_note_id)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (source_file, anchor_key)
+             DO UPDATE SET content_key = excluded.content_key, anki_note_id = excluded.anki_note_id",
+            (source_file, anchor_key, content_key, anki_note_id),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete(&self, source_file: &str, anchor_key: &str) -> Result<(), StoreError> {
+        self.conn.execute(
+            "DELETE FROM notes WHERE source_file = ?1 AND anchor_key = ?2",
+            (source_file, anchor_key),
+        )?;
+        Ok(())
+    }
+
+    /// Best-effort lookup into the pre-upgrade, position-keyed `notes_legacy_v1` table (if it
+    /// exists), keyed by the position a note held in its file: `(note_key, anki_note_id)`.
+    /// Used only to carry an existing Anki note ID forward into the new anchor-keyed schema on
+    /// the first sync of a file after upgrading; once every note in a file has been re-recorded
+    /// under the new scheme, the legacy rows are never consulted again.
+    pub fn legacy_notes_for_file(
+        &self,
+        source_file: &str,
+    ) -> Result<HashMap<i64, (String, i64)>, StoreError> {
+        let legacy_table_exists: bool = self.conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'notes_legacy_v1'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !legacy_table_exists {
+            return Ok(HashMap::new());
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT position, note_key, anki_note_id FROM notes_legacy_v1 WHERE source_file = ?1",
+        )?;
+        let rows = stmt.query_map([source_file], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                (row.get::<_, String>(1)?, row.get::<_, i64>(2)?),
+            ))
+        })?;
+
+        rows.collect::<Result<HashMap<_, _>, _>>()
+            .map_err(StoreError::from)
+    }
+}